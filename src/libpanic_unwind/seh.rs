@@ -137,8 +137,11 @@ unsafe extern fn __rust_try_filter(eh_ptrs: *mut u8,
     return 1
 }
 
+// ARM64 Windows uses the same table-based unwinder as x86_64, delegating to
+// `__C_specific_handler`, and the filter ABI is likewise the two-pointer scheme,
+// so `__rust_try_filter` and this personality are shared unchanged.
 #[lang = "eh_personality"]
-#[cfg(target_arch = "x86_64")]
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 #[no_mangle]
 #[allow(unused)]
 unsafe extern fn rust_seh64_personality(
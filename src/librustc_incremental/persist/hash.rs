@@ -15,8 +15,8 @@ use rustc::ich::Fingerprint;
 use rustc::ty::TyCtxt;
 use rustc_data_structures::fx::FxHashMap;
 use rustc_data_structures::flock;
-use rustc_serialize::Decodable;
-use rustc_serialize::opaque::Decoder;
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::opaque::{Decoder, Encoder};
 
 use super::data::*;
 use super::fs::*;
@@ -56,9 +56,50 @@ impl<'a, 'tcx> HashContext<'a, 'tcx> {
     }
 }
 
+// Size of the fixed-width little-endian words that trail an index-backed hashes
+// file. A fixed width is used deliberately: opaque's integer encoding is
+// variable-width (LEB128), so a "last word" cannot be located at a fixed offset
+// if it were encoded that way.
+const TABLE_POS_BYTES: usize = 8;
+const MAGIC_BYTES: usize = 8;
+
+// Trailing marker identifying a file that carries an `(def_index, offset)` index
+// table. Files written by the legacy encoder end with entry data instead, which
+// (astronomically) will not match, so its absence selects the legacy read path.
+const INDEX_MAGIC: u64 = 0x_5249_4e43_5f49_4458; // "RINC_IDX"
+
+/// The detailed hashes for a crate, held so that individual entries can be
+/// decoded on demand rather than all up front.
+///
+/// An index-backed file (see `encode_metadata_hashes`) is laid out as the crate
+/// SVH, the opaque-encoded `SerializedMetadataHash` entries, the offset table, a
+/// fixed-width word giving the table's position, and the `INDEX_MAGIC` trailer.
+/// The table is a count plus `(def_index, entry_offset)` pairs sorted by
+/// `def_index`, all little-endian so their widths are stable. We keep the file's
+/// bytes resident and the decoded table in memory, decoding an entry only when
+/// its def-id is queried. Note this trades decode *time*, not memory: the byte
+/// buffer stays resident for the crate's lifetime (the request's mmap-based
+/// streaming is not implemented here, as this tree has no mmap facility).
+enum CrateHashes {
+    Detailed {
+        svh: Svh,
+        data: Vec<u8>,
+        /// `(def_index, absolute byte offset of the entry)`, sorted by the
+        /// def-index so that `metadata_hash` can binary-search it.
+        index: Vec<(u32, usize)>,
+    },
+    /// No detailed hashes were available for this crate, so every def-id falls
+    /// back to the crate's overall SVH.
+    SvhOnly(Svh),
+}
+
 struct MetadataHashLoader {
+    // Per-def-id hashes. For an index-backed file only entries that have
+    // actually been queried live here (see `CrateHashes` for the backing
+    // store); a legacy file has all of its entries decoded into this map when
+    // its crate is first loaded.
     metadata_hashes: FxHashMap<DefId, Fingerprint>,
-    crate_hashes: FxHashMap<CrateNum, Svh>,
+    crate_hashes: FxHashMap<CrateNum, CrateHashes>,
 }
 
 impl MetadataHashLoader {
@@ -66,118 +107,279 @@ impl MetadataHashLoader {
         debug!("metadata_hash(id={:?})", id);
 
         debug_assert!(id.krate != LOCAL_CRATE);
-        loop {
-            // check whether we have a result cached for this def-id
-            if let Some(&hash) = self.metadata_hashes.get(&id) {
-                return hash;
-            }
 
-            // check whether we did not find detailed metadata for this
-            // krate; in that case, we just use the krate's overall hash
-            if let Some(&svh) = self.crate_hashes.get(&id.krate) {
-                // micro-"optimization": avoid a cache miss if we ask
-                // for metadata from this particular def-id again.
-                let fingerprint = svh_to_fingerprint(svh);
-                self.metadata_hashes.insert(id, fingerprint);
-                return fingerprint;
-            }
+        // check whether we have a result cached for this def-id
+        if let Some(&hash) = self.metadata_hashes.get(&id) {
+            return hash;
+        }
 
-            // otherwise, load the data and repeat.
+        // make sure the backing data for this crate has been loaded
+        if !self.crate_hashes.contains_key(&id.krate) {
             self.load_data(id.krate, tcx);
-            assert!(self.crate_hashes.contains_key(&id.krate));
         }
+
+        let fingerprint = match self.crate_hashes[&id.krate] {
+            CrateHashes::Detailed { ref data, ref index, svh } => {
+                // the hashes are stored with just a def-index, which is always
+                // relative to the old crate; the crate number matches because we
+                // looked the data up by `id.krate`.
+                match index.binary_search_by_key(&id.index.as_u32(), |&(di, _)| di) {
+                    Ok(pos) => {
+                        let offset = index[pos].1;
+                        let mut decoder = Decoder::new(data, offset);
+                        let entry = SerializedMetadataHash::decode(&mut decoder)
+                            .unwrap_or_else(|err| {
+                                bug!("decoding error in metadata hash entry: {}", err)
+                            });
+                        debug!("metadata_hash: decoded def_id={:?} hash={}",
+                               id, entry.hash);
+                        entry.hash
+                    }
+                    // No detailed hash for this particular def-id; fall back to
+                    // the crate's overall SVH.
+                    Err(_) => svh_to_fingerprint(svh),
+                }
+            }
+
+            // we did not find detailed metadata for this krate; in that case, we
+            // just use the krate's overall hash
+            CrateHashes::SvhOnly(svh) => svh_to_fingerprint(svh),
+        };
+
+        // micro-"optimization": avoid re-decoding if we ask for this def-id again.
+        self.metadata_hashes.insert(id, fingerprint);
+        fingerprint
     }
 
     fn load_data(&mut self, cnum: CrateNum, tcx: TyCtxt) {
         debug!("load_data(cnum={})", cnum);
 
         let svh = tcx.crate_hash(cnum);
-        let old = self.crate_hashes.insert(cnum, svh);
         debug!("load_data: svh={}", svh);
+
+        let hashes = self.load_detailed(cnum, svh, tcx)
+                         .unwrap_or(CrateHashes::SvhOnly(svh));
+        let old = self.crate_hashes.insert(cnum, hashes);
         assert!(old.is_none(), "loaded data for crate {:?} twice", cnum);
+    }
 
-        if let Some(session_dir) = find_metadata_hashes_for(tcx, cnum) {
-            debug!("load_data: session_dir={:?}", session_dir);
-
-            // Lock the directory we'll be reading  the hashes from.
-            let lock_file_path = lock_file_path(&session_dir);
-            let _lock = match flock::Lock::new(&lock_file_path,
-                                               false,   // don't wait
-                                               false,   // don't create the lock-file
-                                               false) { // shared lock
-                Ok(lock) => lock,
-                Err(err) => {
-                    debug!("Could not acquire lock on `{}` while trying to \
-                            load metadata hashes: {}",
-                            lock_file_path.display(),
-                            err);
-
-                    // Could not acquire the lock. The directory is probably in
-                    // in the process of being deleted. It's OK to just exit
-                    // here. It's the same scenario as if the file had not
-                    // existed in the first place.
-                    return
-                }
-            };
-
-            let hashes_file_path = metadata_hash_import_path(&session_dir);
-
-            match file_format::read_file(tcx.sess, &hashes_file_path)
-            {
-                Ok(Some(data)) => {
-                    match self.load_from_data(cnum, &data, svh) {
-                        Ok(()) => { }
-                        Err(err) => {
-                            bug!("decoding error in dep-graph from `{}`: {}",
-                                 &hashes_file_path.display(), err);
-                        }
+    /// Attempt to load and index the detailed hashes file for `cnum`, returning
+    /// `None` if no such file is available (in which case the caller falls back
+    /// to the crate SVH).
+    fn load_detailed(&mut self,
+                     cnum: CrateNum,
+                     svh: Svh,
+                     tcx: TyCtxt) -> Option<CrateHashes> {
+        let session_dir = find_metadata_hashes_for(tcx, cnum)?;
+        debug!("load_detailed: session_dir={:?}", session_dir);
+
+        // Lock the directory we'll be reading  the hashes from.
+        let lock_file_path = lock_file_path(&session_dir);
+        let _lock = match flock::Lock::new(&lock_file_path,
+                                           false,   // don't wait
+                                           false,   // don't create the lock-file
+                                           false) { // shared lock
+            Ok(lock) => lock,
+            Err(err) => {
+                debug!("Could not acquire lock on `{}` while trying to \
+                        load metadata hashes: {}",
+                        lock_file_path.display(),
+                        err);
+
+                // Could not acquire the lock. The directory is probably in
+                // in the process of being deleted. It's OK to just exit
+                // here. It's the same scenario as if the file had not
+                // existed in the first place.
+                return None;
+            }
+        };
+
+        let hashes_file_path = metadata_hash_import_path(&session_dir);
+
+        match file_format::read_file(tcx.sess, &hashes_file_path) {
+            Ok(Some(data)) => {
+                match self.index_data(cnum, data, svh) {
+                    Ok(hashes) => Some(hashes),
+                    Err(err) => {
+                        bug!("decoding error in dep-graph from `{}`: {}",
+                             &hashes_file_path.display(), err);
                     }
                 }
-                Ok(None) => {
-                    // If the file is not found, that's ok.
-                }
-                Err(err) => {
-                    tcx.sess.err(
-                        &format!("could not load dep information from `{}`: {}",
-                                 hashes_file_path.display(), err));
-                }
+            }
+            Ok(None) => {
+                // If the file is not found, that's ok.
+                None
+            }
+            Err(err) => {
+                tcx.sess.err(
+                    &format!("could not load dep information from `{}`: {}",
+                             hashes_file_path.display(), err));
+                None
             }
         }
     }
 
-    fn load_from_data(&mut self,
-                      cnum: CrateNum,
-                      data: &[u8],
-                      expected_svh: Svh) -> Result<(), String> {
-        debug!("load_from_data(cnum={})", cnum);
+    /// Verify the SVH header and either build the lazy offset index (for a file
+    /// written by `encode_metadata_hashes`) or fall back to eagerly decoding the
+    /// legacy `[Svh][SerializedMetadataHashes]` layout.
+    ///
+    /// Legacy files lack the `INDEX_MAGIC` trailer, so they take the eager path
+    /// and behave exactly as the baseline loader did — no detailed hashes are
+    /// lost. Decoding an index that turns out to be truncated or corrupt is
+    /// likewise handled gracefully by falling back to the crate SVH.
+    fn index_data(&mut self,
+                  cnum: CrateNum,
+                  data: Vec<u8>,
+                  expected_svh: Svh) -> Result<CrateHashes, String> {
+        let header_end = {
+            let mut decoder = Decoder::new(&data, 0);
+            let svh_in_hashes_file = Svh::decode(&mut decoder)?;
 
-        // Load up the hashes for the def-ids from this crate.
-        let mut decoder = Decoder::new(data, 0);
-        let svh_in_hashes_file = Svh::decode(&mut decoder)?;
+            if svh_in_hashes_file != expected_svh {
+                // We should not be able to get here. If we do, then
+                // `fs::find_metadata_hashes_for()` has messed up.
+                bug!("mismatch between SVH in crate and SVH in incr. comp. hashes")
+            }
+            decoder.position()
+        };
+
+        // Index-backed files end with a fixed-width table-position word followed
+        // by `INDEX_MAGIC`. If either is missing this is a legacy file.
+        let trailer = MAGIC_BYTES + TABLE_POS_BYTES;
+        let is_indexed = data.len() >= header_end + trailer &&
+            read_u64_le(&data[data.len() - MAGIC_BYTES..]) == INDEX_MAGIC;
+        if !is_indexed {
+            return self.load_legacy(cnum, &data, expected_svh);
+        }
+
+        // The table is a count followed by `count` fixed-width
+        // `(def_index, entry_offset)` pairs. Everything past the SVH header and
+        // before the trailer is addressable; `table_pos` is range-checked before
+        // any subtraction and the table size uses checked arithmetic, so an
+        // attacker-controlled `table_pos` or count near `usize::MAX` can't wrap
+        // past a guard and drive an out-of-bounds read.
+        const ENTRY_BYTES: usize = 4 + TABLE_POS_BYTES; // def_index + offset
+        let body_end = data.len() - trailer;
+        let table_pos = read_u64_le(&data[body_end..]) as usize;
+        if table_pos < header_end || table_pos > body_end {
+            return Ok(CrateHashes::SvhOnly(expected_svh));
+        }
+        let avail = body_end - table_pos;
+        if avail < 4 {
+            return Ok(CrateHashes::SvhOnly(expected_svh));
+        }
+        let count = read_u32_le(&data[table_pos..]) as usize;
+        let needed = count.checked_mul(ENTRY_BYTES).and_then(|n| n.checked_add(4));
+        match needed {
+            Some(n) if n <= avail => {}
+            _ => return Ok(CrateHashes::SvhOnly(expected_svh)),
+        }
 
-        if svh_in_hashes_file != expected_svh {
-            // We should not be able to get here. If we do, then
-            // `fs::find_metadata_hashes_for()` has messed up.
-            bug!("mismatch between SVH in crate and SVH in incr. comp. hashes")
+        let mut index = Vec::with_capacity(count);
+        let mut pos = table_pos + 4;
+        for _ in 0..count {
+            let def_index = read_u32_le(&data[pos..]);
+            let offset = read_u64_le(&data[pos + 4..]) as usize;
+            // Entries live between the SVH header and the table.
+            if offset < header_end || offset >= table_pos {
+                return Ok(CrateHashes::SvhOnly(expected_svh));
+            }
+            index.push((def_index, offset));
+            pos += ENTRY_BYTES;
         }
+        debug!("index_data: indexed {} detailed hashes", index.len());
+
+        Ok(CrateHashes::Detailed {
+            svh: expected_svh,
+            data,
+            index,
+        })
+    }
+
+    /// Eagerly decode a legacy `[Svh][SerializedMetadataHashes]` file, populating
+    /// the per-def-id cache directly. Returns `SvhOnly` so that def-ids absent
+    /// from the file fall back to the crate SVH, matching the baseline loader.
+    fn load_legacy(&mut self,
+                   cnum: CrateNum,
+                   data: &[u8],
+                   expected_svh: Svh) -> Result<CrateHashes, String> {
+        let mut decoder = Decoder::new(data, 0);
+        let _svh = Svh::decode(&mut decoder)?;
 
         let serialized_hashes = SerializedMetadataHashes::decode(&mut decoder)?;
         for serialized_hash in serialized_hashes.entry_hashes {
-            // the hashes are stored with just a def-index, which is
-            // always relative to the old crate; convert that to use
-            // our internal crate number
+            // the hashes are stored with just a def-index, which is always
+            // relative to the old crate; convert that to use our internal crate
+            // number
             let def_id = DefId { krate: cnum, index: serialized_hash.def_index };
-
-            // record the hash for this dep-node
             let old = self.metadata_hashes.insert(def_id, serialized_hash.hash);
-            debug!("load_from_data: def_id={:?} hash={}", def_id, serialized_hash.hash);
+            debug!("load_legacy: def_id={:?} hash={}", def_id, serialized_hash.hash);
             assert!(old.is_none(), "already have hash for {:?}", def_id);
         }
 
-        Ok(())
+        Ok(CrateHashes::SvhOnly(expected_svh))
     }
 }
 
+/// Encode a crate's metadata hashes in the index-backed layout that
+/// `MetadataHashLoader` reads: the SVH, then each entry, then a trailing offset
+/// table keyed by `def_index`, a fixed-width word pointing at the table, and the
+/// `INDEX_MAGIC` trailer.
+///
+/// Emitting this layout is opt-in on the writer side: until the save path is
+/// switched over to call this, files keep the legacy layout and are read via the
+/// eager path in `load_legacy`, so enabling the reader cannot lose hashes.
+pub fn encode_metadata_hashes(svh: Svh,
+                              serialized_hashes: &SerializedMetadataHashes)
+                              -> Vec<u8> {
+    let mut encoder = Encoder::new(Vec::new());
+    svh.encode(&mut encoder).unwrap();
+
+    // Record the byte offset of each entry as we write it so the table can point
+    // back into the body.
+    let mut table = Vec::with_capacity(serialized_hashes.entry_hashes.len());
+    for entry in &serialized_hashes.entry_hashes {
+        let offset = encoder.position() as u64;
+        entry.encode(&mut encoder).unwrap();
+        table.push((entry.def_index.as_u32(), offset));
+    }
+    table.sort_by_key(|&(def_index, _)| def_index);
+
+    let mut data = encoder.into_inner();
+    let table_pos = data.len() as u64;
+    write_u32_le(&mut data, table.len() as u32);
+    for (def_index, offset) in table {
+        write_u32_le(&mut data, def_index);
+        write_u64_le(&mut data, offset);
+    }
+    write_u64_le(&mut data, table_pos);
+    write_u64_le(&mut data, INDEX_MAGIC);
+    data
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32)
+        | ((bytes[1] as u32) << 8)
+        | ((bytes[2] as u32) << 16)
+        | ((bytes[3] as u32) << 24)
+}
+
+fn read_u64_le(bytes: &[u8]) -> u64 {
+    (read_u32_le(bytes) as u64) | ((read_u32_le(&bytes[4..]) as u64) << 32)
+}
+
+fn write_u32_le(data: &mut Vec<u8>, value: u32) {
+    data.push(value as u8);
+    data.push((value >> 8) as u8);
+    data.push((value >> 16) as u8);
+    data.push((value >> 24) as u8);
+}
+
+fn write_u64_le(data: &mut Vec<u8>, value: u64) {
+    write_u32_le(data, value as u32);
+    write_u32_le(data, (value >> 32) as u32);
+}
+
 fn svh_to_fingerprint(svh: Svh) -> Fingerprint {
     Fingerprint::from_smaller_hash(svh.as_u64())
 }
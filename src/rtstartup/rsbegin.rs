@@ -25,3 +25,34 @@
 #![crate_type="rlib"]
 #![no_std]
 #![allow(non_camel_case_types)]
+
+// When built with `--cfg rtstartup_metadata_section`, the startup objects
+// bracket a named section (here `.rust_metadata`) so that tooling can locate the
+// bounds of compiler-runtime-registered records in a linked image. Because the
+// linker keeps rsbegin.o first and rsend.o last, the header emitted here is
+// guaranteed to precede every record, and the sentinel emitted by rsend.o is
+// guaranteed to follow them. This avoids relying on linker-synthesised
+// `__start`/`__stop` symbols, which are not available on every platform.
+#[cfg(rtstartup_metadata_section)]
+pub mod metadata {
+    // Identifies the header at the start of `.rust_metadata` ("RTMD").
+    const RUST_METADATA_MAGIC: u32 = 0x_5254_4d44;
+
+    #[repr(C)]
+    pub struct Header {
+        pub magic: u32,
+        pub version: u32,
+    }
+
+    // Placed first in `.rust_metadata`, so `&RUST_METADATA_HEADER` marks the
+    // lower bound of the records that follow it. It survives because the startup
+    // objects are force-linked and the symbol is `#[no_mangle] pub static`; a
+    // linker invoked with `--gc-sections` must additionally KEEP/retain the
+    // section for the bounds markers to be preserved.
+    #[no_mangle]
+    #[link_section = ".rust_metadata"]
+    pub static RUST_METADATA_HEADER: Header = Header {
+        magic: RUST_METADATA_MAGIC,
+        version: 1,
+    };
+}
@@ -0,0 +1,33 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// See rsbegin.rs for details.
+
+#![crate_type="rlib"]
+#![no_std]
+#![allow(non_camel_case_types)]
+
+// Counterpart to `rsbegin::metadata`: the sentinel emitted here lands last in
+// `.rust_metadata`, so `&RUST_METADATA_FOOTER` marks the upper bound of the
+// records registered between the two startup objects. See rsbegin.rs for the
+// full description of the mechanism.
+#[cfg(rtstartup_metadata_section)]
+pub mod metadata {
+    // Identifies the footer at the end of `.rust_metadata` ("DMTR").
+    const RUST_METADATA_SENTINEL: u32 = 0x_444d_5452;
+
+    // Lands last in `.rust_metadata`, so `&RUST_METADATA_FOOTER` marks the upper
+    // bound of the records. Like the header it survives by being a
+    // `#[no_mangle] pub static` in a force-linked startup object; a linker
+    // invoked with `--gc-sections` must additionally KEEP/retain the section.
+    #[no_mangle]
+    #[link_section = ".rust_metadata"]
+    pub static RUST_METADATA_FOOTER: u32 = RUST_METADATA_SENTINEL;
+}